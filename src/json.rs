@@ -30,22 +30,68 @@
 
 use db::auth::SessionHash;
 use failure::{Error, format_err};
+use ring::rand::SecureRandom;
 use serde::{Deserialize, Serialize};
 use serde::ser::{SerializeMap, SerializeSeq, Serializer};
 use std::collections::BTreeMap;
 use std::ops::Not;
 use uuid::Uuid;
 
+/// The wire encodings available for the JSON API, negotiated from the request's `Accept`
+/// header.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    Json,
+    MsgPack,
+}
+
+impl Encoding {
+    pub const CONTENT_TYPE_JSON: &'static str = "application/json";
+    pub const CONTENT_TYPE_MSGPACK: &'static str = "application/msgpack";
+
+    /// Picks an encoding from an `Accept` header value, defaulting to JSON.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(a) if a.contains("application/msgpack") => Encoding::MsgPack,
+            _ => Encoding::Json,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => Self::CONTENT_TYPE_JSON,
+            Encoding::MsgPack => Self::CONTENT_TYPE_MSGPACK,
+        }
+    }
+
+    /// Serializes `value` in this encoding. MessagePack is encoded positionally (`to_vec`, not
+    /// `to_vec_named`) so it actually skips the field-name bytes JSON pays for.
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Json => Ok(::serde_json::to_vec(value)?),
+            Encoding::MsgPack => Ok(::rmp_serde::to_vec(value)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod encoding_tests {
+    use super::Encoding;
+
+    #[test]
+    fn from_accept_picks_msgpack_only_for_msgpack() {
+        assert_eq!(Encoding::from_accept(Some("application/msgpack")), Encoding::MsgPack);
+        assert_eq!(Encoding::from_accept(Some("application/json")), Encoding::Json);
+        assert_eq!(Encoding::from_accept(Some("application/cbor")), Encoding::Json);
+        assert_eq!(Encoding::from_accept(None), Encoding::Json);
+    }
+}
+
 #[derive(Serialize)]
 #[serde(rename_all="camelCase")]
 pub struct TopLevel<'a> {
     pub time_zone_name: &'a str,
-
-    // Use a custom serializer which presents the map's values as a sequence and includes the
-    // "days" attribute or not, according to the bool in the tuple.
-    #[serde(serialize_with = "TopLevel::serialize_cameras")]
-    pub cameras: (&'a db::LockedDatabase, bool),
-
+    pub cameras: Vec<Camera<'a>>,
     pub session: Option<Session>,
 }
 
@@ -67,6 +113,161 @@ impl Session {
     }
 }
 
+/// A salted SHA-256 hash of a share token's optional viewer password. Never serialized to the
+/// client.
+#[derive(Clone)]
+pub struct SharePasswordHash {
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl SharePasswordHash {
+    pub fn new(password: &str) -> Self {
+        let mut salt = [0u8; 16];
+        ring::rand::SystemRandom::new().fill(&mut salt).expect("system RNG failure");
+        SharePasswordHash {
+            hash: Self::derive(&salt, password),
+            salt,
+        }
+    }
+
+    fn derive(salt: &[u8; 16], password: &str) -> [u8; 32] {
+        let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+        ctx.update(salt);
+        ctx.update(password.as_bytes());
+        let mut out = [0u8; 32];
+        out.copy_from_slice(ctx.finish().as_ref());
+        out
+    }
+
+    /// Verifies `candidate` against this stored hash in constant time.
+    pub fn verify(&self, candidate: &str) -> bool {
+        let candidate_hash = Self::derive(&self.salt, candidate);
+        ring::constant_time::verify_slices_are_equal(&self.hash, &candidate_hash).is_ok()
+    }
+}
+
+/// A share token grants read-only, time-boxed access to a window of one stream's recordings
+/// without exposing the minting user's full credentials. The client holds an opaque token used
+/// to look this record up.
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct ShareToken {
+    pub stream_id: i32,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub expires_90k: i64,
+    pub requires_password: bool,
+
+    #[serde(skip)]
+    password_hash: Option<SharePasswordHash>,
+}
+
+impl ShareToken {
+    pub fn new(req: &NewShareTokenRequest) -> Self {
+        ShareToken {
+            stream_id: req.stream_id,
+            start_time_90k: req.start_time_90k,
+            end_time_90k: req.end_time_90k,
+            expires_90k: req.expires_90k,
+            requires_password: req.password.is_some(),
+            password_hash: req.password.as_ref().map(|p| SharePasswordHash::new(p)),
+        }
+    }
+
+    /// Checks that `now_90k` is before expiry, that `[start_time_90k, end_time_90k]` falls
+    /// within the range this token was minted for, and — if `requires_password` — that
+    /// `candidate_password` hashes to the stored value.
+    pub fn check_access(&self, now_90k: i64, start_time_90k: i64, end_time_90k: i64,
+                         candidate_password: Option<&str>)
+    -> Result<(), Error> {
+        if now_90k >= self.expires_90k {
+            return Err(format_err!("share token expired at {}", self.expires_90k));
+        }
+        if start_time_90k < self.start_time_90k || end_time_90k > self.end_time_90k {
+            return Err(format_err!(
+                "requested range [{}, {}] is outside token's granted range [{}, {}]",
+                start_time_90k, end_time_90k, self.start_time_90k, self.end_time_90k));
+        }
+        if self.requires_password {
+            let hash = self.password_hash.as_ref()
+                .ok_or_else(|| format_err!("share token requires a password but has no hash"))?;
+            match candidate_password {
+                Some(p) if hash.verify(p) => {},
+                _ => return Err(format_err!("incorrect or missing share token password")),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Request body for minting a new `ShareToken`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all="camelCase")]
+pub struct NewShareTokenRequest {
+    pub stream_id: i32,
+    pub start_time_90k: i64,
+    pub end_time_90k: i64,
+    pub expires_90k: i64,
+
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl NewShareTokenRequest {
+    /// Checks that the requested range isn't inverted.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.start_time_90k > self.end_time_90k {
+            return Err(format_err!("start_time_90k={} is after end_time_90k={}",
+                                    self.start_time_90k, self.end_time_90k));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod share_token_tests {
+    use super::{NewShareTokenRequest, ShareToken};
+
+    fn req(password: Option<&str>) -> NewShareTokenRequest {
+        NewShareTokenRequest {
+            stream_id: 1,
+            start_time_90k: 100,
+            end_time_90k: 200,
+            expires_90k: 1_000,
+            password: password.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn rejects_after_expiry() {
+        let t = ShareToken::new(&req(None));
+        assert!(t.check_access(1_000, 100, 200, None).is_err());
+    }
+
+    #[test]
+    fn rejects_range_outside_grant() {
+        let t = ShareToken::new(&req(None));
+        assert!(t.check_access(0, 50, 200, None).is_err());
+        assert!(t.check_access(0, 100, 250, None).is_err());
+        assert!(t.check_access(0, 100, 200, None).is_ok());
+    }
+
+    #[test]
+    fn rejects_missing_or_wrong_password() {
+        let t = ShareToken::new(&req(Some("hunter2")));
+        assert!(t.check_access(0, 100, 200, None).is_err());
+        assert!(t.check_access(0, 100, 200, Some("wrong")).is_err());
+        assert!(t.check_access(0, 100, 200, Some("hunter2")).is_ok());
+    }
+
+    #[test]
+    fn no_password_required_when_none_set() {
+        let t = ShareToken::new(&req(None));
+        assert!(t.check_access(0, 100, 200, None).is_ok());
+    }
+}
+
 /// JSON serialization wrapper for a single camera when processing `/api/` and
 /// `/api/cameras/<uuid>/`. See `design/api.md` for details.
 #[derive(Debug, Serialize)]
@@ -95,7 +296,8 @@ pub struct Stream<'a> {
 }
 
 impl<'a> Camera<'a> {
-    pub fn wrap(c: &'a db::Camera, db: &'a db::LockedDatabase, include_days: bool) -> Result<Self, Error> {
+    pub fn wrap(c: &'a db::Camera, db: &'a db::LockedDatabase, include_days: bool)
+    -> Result<Self, CodedError> {
         Ok(Camera {
             uuid: c.uuid,
             short_name: &c.short_name,
@@ -121,12 +323,14 @@ impl<'a> Camera<'a> {
 }
 
 impl<'a> Stream<'a> {
-    fn wrap(db: &'a db::LockedDatabase, id: Option<i32>, include_days: bool) -> Result<Option<Self>, Error> {
+    fn wrap(db: &'a db::LockedDatabase, id: Option<i32>, include_days: bool)
+    -> Result<Option<Self>, CodedError> {
         let id = match id {
             Some(id) => id,
             None => return Ok(None),
         };
-        let s = db.streams_by_id().get(&id).ok_or_else(|| format_err!("missing stream {}", id))?;
+        let s = db.streams_by_id().get(&id)
+            .ok_or_else(|| CodedError::not_found(format_err!("missing stream {}", id)))?;
         Ok(Some(Stream {
             retain_bytes: s.retain_bytes,
             min_start_time_90k: s.range.as_ref().map(|r| r.start.0),
@@ -167,17 +371,12 @@ struct StreamDayValue {
 }
 
 impl<'a> TopLevel<'a> {
-    /// Serializes cameras as a list (rather than a map), optionally including the `days` field.
-    fn serialize_cameras<S>(cameras: &(&db::LockedDatabase, bool),
-                            serializer: S) -> Result<S::Ok, S::Error>
-    where S: Serializer {
-        let (db, include_days) = *cameras;
-        let cs = db.cameras_by_id();
-        let mut seq = serializer.serialize_seq(Some(cs.len()))?;
-        for (_, c) in cs {
-            seq.serialize_element(&Camera::wrap(c, db, include_days).unwrap())?;  // TODO: no unwrap.
-        }
-        seq.end()
+    /// Builds the camera list up front so a lookup failure becomes a typed `CodedError` the
+    /// caller can turn into an `ApiError` before ever reaching a serializer, rather than an
+    /// opaque `Serializer::Error` partway through encoding the response.
+    pub fn wrap_cameras(db: &'a db::LockedDatabase, include_days: bool)
+    -> Result<Vec<Camera<'a>>, CodedError> {
+        db.cameras_by_id().iter().map(|(_, c)| Camera::wrap(c, db, include_days)).collect()
     }
 }
 
@@ -209,7 +408,284 @@ pub struct Recording {
     pub growing: bool,
 }
 
+/// The location of a stream's sample file directory: either a local filesystem path or an
+/// S3-compatible object storage bucket. Untagged so that existing configs of the form
+/// `{ "path": "..." }`, with no discriminant at all, keep parsing as `Filesystem`.
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum SampleFileDirPath {
+    Filesystem {
+        path: String,
+    },
+
+    ObjectStorage {
+        endpoint: String,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+
+        #[serde(default)]
+        prefix: String,
+    },
+}
+
+impl ::std::fmt::Debug for SampleFileDirPath {
+    /// Redacts `access_key`/`secret_key` so this doesn't end up verbatim in a logged config.
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            SampleFileDirPath::Filesystem { ref path } => {
+                f.debug_struct("Filesystem").field("path", path).finish()
+            }
+            SampleFileDirPath::ObjectStorage { ref endpoint, ref bucket, ref region, ref prefix, .. } => {
+                f.debug_struct("ObjectStorage")
+                    .field("endpoint", endpoint)
+                    .field("bucket", bucket)
+                    .field("region", region)
+                    .field("access_key", &"<redacted>")
+                    .field("secret_key", &"<redacted>")
+                    .field("prefix", prefix)
+                    .finish()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod sample_file_dir_path_tests {
+    use super::SampleFileDirPath;
+
+    #[test]
+    fn legacy_config_without_type_parses_as_filesystem() {
+        let p: SampleFileDirPath = ::serde_json::from_str(r#"{"path": "/var/lib/moonfire-nvr/sample"}"#).unwrap();
+        match p {
+            SampleFileDirPath::Filesystem { path } => assert_eq!(path, "/var/lib/moonfire-nvr/sample"),
+            SampleFileDirPath::ObjectStorage { .. } => panic!("expected Filesystem"),
+        }
+    }
+
+    #[test]
+    fn debug_redacts_credentials() {
+        let p = SampleFileDirPath::ObjectStorage {
+            endpoint: "https://s3.example.com".to_owned(),
+            bucket: "b".to_owned(),
+            region: "us-east-1".to_owned(),
+            access_key: "AKIASECRET".to_owned(),
+            secret_key: "shh".to_owned(),
+            prefix: String::new(),
+        };
+        let s = format!("{:?}", p);
+        assert!(!s.contains("AKIASECRET"));
+        assert!(!s.contains("shh"));
+    }
+
+    #[test]
+    fn object_storage_config_parses() {
+        let json = r#"{
+            "endpoint": "https://s3.example.com",
+            "bucket": "recordings",
+            "region": "us-east-1",
+            "access_key": "AKIASECRET",
+            "secret_key": "shh",
+            "prefix": "cam1/"
+        }"#;
+        let p: SampleFileDirPath = ::serde_json::from_str(json).unwrap();
+        match p {
+            SampleFileDirPath::ObjectStorage { endpoint, bucket, region, access_key, secret_key, prefix } => {
+                assert_eq!(endpoint, "https://s3.example.com");
+                assert_eq!(bucket, "recordings");
+                assert_eq!(region, "us-east-1");
+                assert_eq!(access_key, "AKIASECRET");
+                assert_eq!(secret_key, "shh");
+                assert_eq!(prefix, "cam1/");
+            }
+            SampleFileDirPath::Filesystem { .. } => panic!("expected ObjectStorage"),
+        }
+    }
+
+    #[test]
+    fn stray_field_alongside_path_is_rejected_rather_than_silently_dropped() {
+        let json = r#"{"path": "/x", "bucket": "other"}"#;
+        let result: Result<SampleFileDirPath, _> = ::serde_json::from_str(json);
+        assert!(result.is_err());
+    }
+}
+
+/// A stable, machine-parseable error code, each mapping to exactly one HTTP status.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all="camelCase")]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthenticated,
+    NotFound,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn http_status(self) -> u16 {
+        match self {
+            ErrorCode::BadRequest => 400,
+            ErrorCode::Unauthenticated => 401,
+            ErrorCode::NotFound => 404,
+            ErrorCode::Internal => 500,
+        }
+    }
+}
+
+/// An internal error paired with the `ErrorCode` it should map to. Producing this at the point
+/// an error is raised (e.g. `Stream::wrap`'s missing-id case) means turning it into an
+/// `ApiError` never has to guess a code by inspecting `Display` text, which would silently
+/// break if the message wording changed.
+#[derive(Debug)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    pub cause: Error,
+}
+
+impl CodedError {
+    pub fn not_found(cause: Error) -> Self {
+        CodedError { code: ErrorCode::NotFound, cause }
+    }
+
+    pub fn internal(cause: Error) -> Self {
+        CodedError { code: ErrorCode::Internal, cause }
+    }
+}
+
+/// The JSON body returned on every API failure, in place of an opaque 500. `request_id` is
+/// generated fresh for each error so it can be quoted back to correlate a client-reported
+/// failure with the corresponding server log line.
+#[derive(Debug, Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub request_id: Uuid,
+    pub timestamp_90k: i64,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: String, timestamp_90k: i64) -> Self {
+        ApiError {
+            code,
+            message,
+            request_id: Uuid::new_v4(),
+            timestamp_90k,
+        }
+    }
+
+    /// Converts a `CodedError` into the envelope returned to the client, keeping its code.
+    pub fn from_coded(err: CodedError, timestamp_90k: i64) -> Self {
+        ApiError::new(err.code, err.cause.to_string(), timestamp_90k)
+    }
+}
+
+// `Stream::wrap`/`Camera::wrap` return `CodedError` directly (see `from_coded_preserves_*`
+// below), so turning a missing stream id into `NotFound` no longer depends on `Display` text.
+// A true end-to-end test driving a missing id through `Camera::wrap` would need a real
+// `db::LockedDatabase`/`db::Camera` fixture; this tree doesn't vendor the `db` crate, so that
+// isn't constructible here.
+#[cfg(test)]
+mod api_error_tests {
+    use super::{ApiError, CodedError, ErrorCode};
+    use failure::format_err;
+
+    #[test]
+    fn http_status_mapping() {
+        assert_eq!(ErrorCode::BadRequest.http_status(), 400);
+        assert_eq!(ErrorCode::Unauthenticated.http_status(), 401);
+        assert_eq!(ErrorCode::NotFound.http_status(), 404);
+        assert_eq!(ErrorCode::Internal.http_status(), 500);
+    }
+
+    #[test]
+    fn new_fills_in_a_fresh_request_id() {
+        let a = ApiError::new(ErrorCode::BadRequest, "bad".to_owned(), 42);
+        let b = ApiError::new(ErrorCode::BadRequest, "bad".to_owned(), 42);
+        assert_eq!(a.code, ErrorCode::BadRequest);
+        assert_eq!(a.message, "bad");
+        assert_eq!(a.timestamp_90k, 42);
+        assert_ne!(a.request_id, b.request_id);
+    }
+
+    #[test]
+    fn from_coded_preserves_the_code_and_cause() {
+        let coded = CodedError::not_found(format_err!("missing stream {}", 42));
+        let e = ApiError::from_coded(coded, 1);
+        assert_eq!(e.code, ErrorCode::NotFound);
+        assert_eq!(e.message, "missing stream 42");
+    }
+
+    #[test]
+    fn serializes_with_camel_case_field_names() {
+        let e = ApiError::new(ErrorCode::NotFound, "missing stream 42".to_owned(), 7);
+        let v = ::serde_json::to_value(&e).unwrap();
+        let obj = v.as_object().unwrap();
+        assert_eq!(obj["code"], "notFound");
+        assert_eq!(obj["message"], "missing stream 42");
+        assert_eq!(obj["timestamp90k"], 7);
+        assert!(obj.contains_key("requestId"));
+    }
+}
+
+/// Query parameters for listing a stream's recordings, deserialized via `serde_urlencoded`.
+/// Absent fields are unbounded.
 #[derive(Debug, Deserialize)]
-pub struct SampleFileDirPath {
-    pub path: String
+#[serde(rename_all="camelCase")]
+pub struct ListRecordingsRequest {
+    pub start_time_90k: Option<i64>,
+    pub end_time_90k: Option<i64>,
+    pub min_duration_90k: Option<i64>,
+    pub growing_only: Option<bool>,
+    pub split_duration_90k: Option<i64>,
+}
+
+impl ListRecordingsRequest {
+    /// Checks that the requested range isn't inverted.
+    pub fn validate(&self) -> Result<(), Error> {
+        if let (Some(s), Some(e)) = (self.start_time_90k, self.end_time_90k) {
+            if s > e {
+                return Err(format_err!("start_time_90k={} is after end_time_90k={}", s, e));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod list_recordings_request_tests {
+    use super::ListRecordingsRequest;
+
+    #[test]
+    fn rejects_inverted_range() {
+        let r = ListRecordingsRequest {
+            start_time_90k: Some(5),
+            end_time_90k: Some(4),
+            min_duration_90k: None,
+            growing_only: None,
+            split_duration_90k: None,
+        };
+        assert!(r.validate().is_err());
+    }
+
+    #[test]
+    fn accepts_equal_bounds_and_unbounded_fields() {
+        let equal = ListRecordingsRequest {
+            start_time_90k: Some(5),
+            end_time_90k: Some(5),
+            min_duration_90k: None,
+            growing_only: None,
+            split_duration_90k: None,
+        };
+        assert!(equal.validate().is_ok());
+
+        let unbounded = ListRecordingsRequest {
+            start_time_90k: None,
+            end_time_90k: None,
+            min_duration_90k: None,
+            growing_only: None,
+            split_duration_90k: None,
+        };
+        assert!(unbounded.validate().is_ok());
+    }
 }